@@ -0,0 +1,153 @@
+use super::Locked;
+use super::linked_list::LinkedListAllocator;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+
+/* The block sizes to use.
+ *
+ * The sizes have to each be a power of two because they're also used as the block's alignment
+ * (alignments must always be a power of two). Small, frequent kernel allocations (Box/Vec churn)
+ * land in one of these classes and get served in O(1); anything bigger falls back to the
+ * LinkedListAllocator.
+ */
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+}
+
+impl FixedSizeBlockAllocator {
+    // will be hard to refactor to RAII cause new() needs to be const for compile-time eval, but
+    // init() can only be called at runtime when we've got the address of a page.
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        Self {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+        }
+    }
+
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    // Rounds the layout up to the smallest block class that can hold it (both in size and in
+    // alignment, since every block in a class is used as that class's alignment too).
+    fn list_index(layout: &Layout) -> Option<usize> {
+        let required_block_size = layout.size().max(layout.align());
+        BLOCK_SIZES.iter().position(|&size| size >= required_block_size)
+    }
+
+    unsafe fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.fallback_allocator.allocate(layout)
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+
+        match FixedSizeBlockAllocator::list_index(&layout) {
+            Some(index) => {
+                match allocator.list_heads[index].take() {
+                    Some(node) => {
+                        // pop a free block straight off the class's list: O(1).
+                        allocator.list_heads[index] = node.next.take();
+                        node as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        // list for this class is empty: carve a fresh block of the class size
+                        // from the fallback allocator.
+                        let block_size = BLOCK_SIZES[index];
+                        // every block size is also its alignment, enforced by list_index().
+                        let block_align = block_size;
+                        match Layout::from_size_align(block_size, block_align) {
+                            Ok(layout) => allocator.fallback_alloc(layout),
+                            Err(_) => ptr::null_mut(),
+                        }
+                    }
+                }
+            }
+            None => allocator.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+
+        match FixedSizeBlockAllocator::list_index(&layout) {
+            Some(index) => {
+                // push the freed block back onto its class's list, reusing the block's own
+                // memory to store the next pointer rather than returning it to the fallback
+                // allocator's free list.
+                let new_node = ListNode {
+                    next: allocator.list_heads[index].take(),
+                };
+                debug_assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                debug_assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let node_ptr = ptr as *mut ListNode;
+                node_ptr.write(new_node);
+                allocator.list_heads[index] = Some(&mut *node_ptr);
+            }
+            None => {
+                allocator.fallback_allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // See linked_list.rs's test module for why unit tests lean on `std` for a stable heap
+    // address instead of a stack array.
+    extern crate std;
+
+    use super::*;
+    use std::boxed::Box;
+
+    #[repr(align(64))]
+    struct AlignedHeap<const N: usize>([u8; N]);
+
+    fn new_heap<const N: usize>() -> (Locked<FixedSizeBlockAllocator>, Box<AlignedHeap<N>>) {
+        let mut heap = Box::new(AlignedHeap([0u8; N]));
+        let allocator = Locked::new(FixedSizeBlockAllocator::new());
+        unsafe {
+            allocator.lock().init(heap.0.as_mut_ptr() as usize, N);
+        }
+        (allocator, heap)
+    }
+
+    #[test]
+    fn reuses_a_freed_block_from_its_size_class() {
+        let (allocator, _heap) = new_heap::<4096>();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+
+        unsafe {
+            let first = allocator.alloc(layout);
+            assert!(!first.is_null());
+
+            allocator.dealloc(first, layout);
+
+            // The class list had exactly one free block (the one we just returned), so the next
+            // same-class request must pop it straight back off the list rather than carving a
+            // fresh one from the fallback allocator.
+            let second = allocator.alloc(layout);
+            assert_eq!(first, second);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_linked_list_allocator_for_oversized_requests() {
+        let (allocator, _heap) = new_heap::<8192>();
+        // Bigger than the largest block class (2048), so this has to go through fallback_alloc.
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+    }
+}
@@ -1,12 +1,11 @@
 use super::Locked;
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::alloc::{AllocError, Allocator};
 use core::ptr;
+use core::ptr::NonNull;
 use super::align_up;
 use core::mem;
 
-/* TODO:
- * - avoid fragmentation by inserting free ListNodes into the list in order of start address; deallocate() can then merge with adjacent blocks.
- */
 
 struct ListNode {
     size: usize,
@@ -35,43 +34,138 @@ impl ListNode {
 }
 
 
+// Controls how `find_region` picks among the free regions big enough to serve a request.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FitPolicy {
+    // Take the first region found that fits; O(1) best case but leaves fragmentation behind.
+    FirstFit,
+    // Scan every region and take the one that wastes the least space; more work per allocation,
+    // less fragmentation over time.
+    BestFit,
+}
+
 pub struct LinkedListAllocator {
     head: ListNode,
+    policy: FitPolicy,
 }
 
 impl LinkedListAllocator {
     // will be hard to refactor to RAII cause new() needs to be const for compile-time eval, but
     // init() can only be called at runtime when we've got the address of a page.
     pub const fn new() -> Self {
+        Self::with_policy(FitPolicy::FirstFit)
+    }
+
+    pub const fn with_policy(policy: FitPolicy) -> Self {
         Self {
             head: ListNode::new(0),
+            policy,
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
-        self.add_free_region(heap_start, heap_size);
+        // The initial heap region comes from the kernel's own boot-time setup, not from a
+        // caller-supplied `Layout`, so an invalid region here is a kernel bug, not something we
+        // need to degrade gracefully from.
+        self.add_free_region(heap_start, heap_size)
+            .expect("initial heap region is too small or misaligned");
+    }
+
+    // Inserts the region in address order (rather than just pushing onto `head.next`) so that
+    // adjacent free blocks can be detected and merged, keeping the heap from fragmenting into
+    // lots of small blocks over repeated alloc/dealloc cycles.
+    //
+    // Rejects regions that are too small or insufficiently aligned instead of asserting, so a
+    // misbehaving `dealloc` can't bring down the whole kernel.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) -> Result<(), ()> {
+        if align_up(addr, mem::align_of::<ListNode>()) != addr || size < mem::size_of::<ListNode>() {
+            return Err(());
+        }
+
+        // Walk to the last node whose start address is before `addr`. `head` has size 0 and
+        // thus never qualifies as a real predecessor, so it always sorts first.
+        let mut current = &mut self.head;
+        while let Some(ref region) = current.next {
+            if region.start_addr() >= addr {
+                break;
+            }
+            current = current.next.as_mut().unwrap();
+        }
+
+        // Coalesce with the predecessor if it directly abuts the new block; otherwise link in a
+        // fresh node.
+        if current.size != 0 && current.end_addr() == addr {
+            current.size += size;
+        } else {
+            let mut node = ListNode::new(size);
+            node.next = current.next.take();
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr);
+            current = current.next.as_mut().unwrap();
+        }
+
+        // Coalesce the (possibly just-grown) block with its successor if they're adjacent too.
+        let current_end = current.end_addr();
+        let merges_with_successor = current.next.as_ref()
+            .map_or(false, |successor| successor.start_addr() == current_end);
+        if merges_with_successor {
+            let successor = current.next.take().unwrap();
+            current.size += successor.size;
+            current.next = successor.next.take();
+        }
+
+        Ok(())
     }
 
-    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
-        // TODO dunno why these are asserts? Will alloc() be making sure only compliant regions are
-        // handed out?
-        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
-        assert!(size >= mem::size_of::<ListNode>());
+    // Finds a region to serve `size`/`align` according to `self.policy`, unlinks it from the
+    // free list and returns it along with the `alloc_start` to hand out.
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        match self.policy {
+            FitPolicy::FirstFit => {
+                // today's behavior: take the first region `alloc_from_region` accepts.
+                self.alloc_node(|region| Self::alloc_from_region(region, size, align))
+            }
+            FitPolicy::BestFit => {
+                // Full pass over the free list (read-only) to find the region with the smallest
+                // non-negative excess, then a second pass through `alloc_node` to unlink it -
+                // reusing the exact same rejection rule (and unlinking machinery) as first-fit.
+                let mut best: Option<(usize, usize, usize)> = None; // (region_start, alloc_start, excess)
+
+                let mut current = &mut self.head;
+                while let Some(ref mut region) = current.next {
+                    if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
+                        let excess = region.end_addr() - (alloc_start + size);
+                        if best.map_or(true, |(_, _, best_excess)| excess < best_excess) {
+                            best = Some((region.start_addr(), alloc_start, excess));
+                        }
+                    }
+                    current = current.next.as_mut().unwrap();
+                }
 
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr);
+                let (best_start, best_alloc_start, _) = best?;
+                self.alloc_node(|region| {
+                    if region.start_addr() == best_start {
+                        Ok(best_alloc_start)
+                    } else {
+                        Err(())
+                    }
+                })
+            }
+        }
     }
 
-    fn find_region(&mut self, size: usize, align: usize)
-        -> Option<(&'static mut ListNode, usize)>
-    {
+    // Scans the free list for the first node `predicate` accepts, unlinks it and returns it
+    // along with the `alloc_start` the predicate computed. This is the scan-and-unlink machinery
+    // shared by every fit policy; policies differ only in how they build `predicate`.
+    fn alloc_node(
+        &mut self,
+        mut predicate: impl FnMut(&mut ListNode) -> Result<usize, ()>,
+    ) -> Option<(&'static mut ListNode, usize)> {
         let mut current = &mut self.head;
 
         while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
+            if let Ok(alloc_start) = predicate(region) {
                 // region suitable for allocation
                 let next = region.next.take();
                 let ret = Some((current.next.take().unwrap(), alloc_start));
@@ -87,6 +181,31 @@ impl LinkedListAllocator {
         None
     }
 
+    // Finds the free region starting exactly at `addr`, unlinks it and hands it back. Used by
+    // `grow` to check whether the block immediately after an existing allocation is free, so
+    // that growth can extend into it in place instead of copying.
+    fn take_region_at(&mut self, addr: usize) -> Option<&'static mut ListNode> {
+        let mut current = &mut self.head;
+
+        loop {
+            let found = match current.next {
+                Some(ref region) if region.start_addr() == addr => true,
+                // list is address-ordered, so once we've passed `addr` there's nothing left to find.
+                Some(ref region) if region.start_addr() > addr => return None,
+                Some(_) => false,
+                None => return None,
+            };
+
+            if found {
+                let node = current.next.take().unwrap();
+                current.next = node.next.take();
+                return Some(node);
+            }
+
+            current = current.next.as_mut().unwrap();
+        }
+    }
+
     // Dunno why this returns Result<T, ()> rather than Option<T>.
     // All I can think of is so ? can be used in one place.
     fn alloc_from_region(region: &ListNode, size: usize, align: usize)
@@ -128,19 +247,23 @@ impl LinkedListAllocator {
         let size = layout.size().max(mem::size_of::<ListNode>());
         (size, layout.align())
     }
-}
 
+    // Core of `alloc`, pulled out so the fixed-size-block front-end can carve fresh blocks from
+    // this allocator without going through the `Locked<LinkedListAllocator>` `GlobalAlloc` impl.
+    pub(super) unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
 
-unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.lock();
-
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("overflow");
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let alloc_end = match alloc_start.checked_add(size) {
+                Some(alloc_end) => alloc_end,
+                // Overflowing here would mean `size` is absurdly large; report OOM rather than
+                // abort the kernel.
+                None => return ptr::null_mut(),
+            };
             let excess_size = region.end_addr() - alloc_end;
             if excess_size > 0 { // guarenteed by find_region to be big enough to hold the free list element.
-                allocator.add_free_region(alloc_end, excess_size);
+                self.add_free_region(alloc_end, excess_size)
+                    .expect("excess region computed by find_region must be valid");
             }
             alloc_start as *mut u8
         } else {
@@ -148,9 +271,270 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         }
     }
 
+    // Core of `dealloc`, pulled out for the same reason as `allocate`.
+    pub(super) unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+
+        // A `dealloc` call always carries the same `Layout` (and thus the same `size_align`
+        // result) that was used to allocate `ptr`, so the region it hands back is guaranteed to
+        // be `ListNode`-aligned and big enough; any failure here would indicate memory
+        // corruption, which we can't recover from anyway.
+        let _ = self.add_free_region(ptr as usize, size);
+    }
+}
+
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout)
+    }
+}
+
+// Requires `#![feature(allocator_api)]` in the crate root, same as the other unstable bits
+// (`abi_x86_interrupt` etc.) this kernel already relies on. Lets callers hand a specific
+// `Locked<LinkedListAllocator>` to `Vec::new_in`/`Box::new_in` to get an isolated sub-heap
+// instead of going through the single `#[global_allocator]` slot.
+unsafe impl Allocator for Locked<LinkedListAllocator> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            // `Layout::dangling`/`dangling_ptr` isn't available on every toolchain this crate has
+            // been built with, so construct the well-aligned, never-dereferenced pointer by hand
+            // the same way those helpers do internally.
+            let dangling = unsafe { NonNull::new_unchecked(layout.align() as *mut u8) };
+            return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+        }
+
+        // Delegate to the same core `LinkedListAllocator::allocate` that backs the `GlobalAlloc`
+        // impl, rather than re-deriving the find-region/split-excess sequence here.
+        let raw = unsafe { self.lock().allocate(layout) };
+        let ptr = NonNull::new(raw).ok_or(AllocError)?;
+
+        // `size`, not the raw `layout.size()`, is the true usable size: `size_align` pads the
+        // request up to `ListNode`'s alignment/size requirements, and `LinkedListAllocator::allocate`
+        // only ever hands back regions big enough for that padded size, so the caller can safely
+        // use all of it.
         let (size, _) = LinkedListAllocator::size_align(layout);
+        Ok(NonNull::slice_from_raw_parts(ptr, size))
+    }
 
-        self.lock().add_free_region(ptr as usize, size)
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+
+        self.lock().deallocate(ptr.as_ptr(), layout)
+    }
+
+    // Avoids the default allocate-copy-deallocate cycle when the block immediately following
+    // this allocation happens to be free and big enough: growth just absorbs it in place.
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+
+        let (old_size, old_align) = LinkedListAllocator::size_align(old_layout);
+        let (new_size, new_align) = LinkedListAllocator::size_align(new_layout);
+
+        if new_align <= old_align && new_size > old_size {
+            let needed = new_size - old_size;
+            let follow_addr = ptr.as_ptr() as usize + old_size;
+            let mut allocator = self.lock();
+
+            if let Some(node) = allocator.take_region_at(follow_addr) {
+                if node.size >= needed {
+                    let leftover = node.size - needed;
+                    if leftover >= mem::size_of::<ListNode>() {
+                        allocator.add_free_region(follow_addr + needed, leftover)
+                            .expect("leftover region must be valid");
+                        return Ok(NonNull::slice_from_raw_parts(ptr, new_size));
+                    } else {
+                        // leftover too small to stand as its own free region: fold it into this
+                        // allocation, reporting it in the returned length.
+                        return Ok(NonNull::slice_from_raw_parts(ptr, new_size + leftover));
+                    }
+                } else {
+                    // not big enough after all; hand the region straight back.
+                    allocator.add_free_region(node.start_addr(), node.size)
+                        .expect("region must still be valid");
+                }
+            }
+        }
+
+        // In-place growth isn't possible: fall back to allocate-copy-deallocate.
+        let new_ptr = self.allocate(new_layout)?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr() as *mut u8, old_layout.size());
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+
+    // Shrinking to a layout that needs no stricter alignment than the old one keeps the same
+    // pointer: we just hand any newly-trailing space back to the free list when there's enough of
+    // it to be useful. A stricter alignment moves the data to a fresh allocation instead.
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() <= old_layout.size());
+
+        let (old_size, old_align) = LinkedListAllocator::size_align(old_layout);
+        let (new_size, new_align) = LinkedListAllocator::size_align(new_layout);
+
+        // `new_align` padding `new_size` above `old_size` (a stricter alignment can do that) would
+        // underflow a plain subtraction, and in any case this pointer was only ever allocated to
+        // satisfy `old_align` - if the new layout needs more alignment than that, keeping it would
+        // hand out a pointer that doesn't actually meet the caller's alignment requirement.
+        if new_align <= old_align {
+            if let Some(remainder) = old_size.checked_sub(new_size) {
+                if remainder >= mem::size_of::<ListNode>() {
+                    let addr = ptr.as_ptr() as usize;
+                    self.lock().add_free_region(addr + new_size, remainder)
+                        .expect("trailing remainder must be a valid free region");
+                    return Ok(NonNull::slice_from_raw_parts(ptr, new_size));
+                }
+
+                // Remainder is too small to free on its own: keep the whole block rather than
+                // fragmenting it into something unusable.
+                return Ok(NonNull::slice_from_raw_parts(ptr, old_size));
+            }
+        }
+
+        // In-place shrink isn't possible: fall back to allocate-copy-deallocate, mirroring `grow`.
+        let new_ptr = self.allocate(new_layout)?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr() as *mut u8, new_layout.size());
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Unit tests run on the host (see the crate root's `cfg_attr(test, ...)` split between
+    // `no_std` and std), so we can lean on `std::boxed::Box` to get a heap buffer with a stable
+    // address - a stack array would move (and invalidate the free list's pointers into it) the
+    // moment it's handed back out of a helper function.
+    extern crate std;
+
+    use super::*;
+    use std::boxed::Box;
+
+    // Heap buffers need to satisfy `ListNode`'s alignment or `init` rejects them outright.
+    #[repr(align(64))]
+    struct AlignedHeap<const N: usize>([u8; N]);
+
+    fn new_heap<const N: usize>(policy: FitPolicy) -> (Locked<LinkedListAllocator>, Box<AlignedHeap<N>>) {
+        let mut heap = Box::new(AlignedHeap([0u8; N]));
+        let allocator = Locked::new(LinkedListAllocator::with_policy(policy));
+        unsafe {
+            allocator.lock().init(heap.0.as_mut_ptr() as usize, N);
+        }
+        (allocator, heap)
+    }
+
+    #[test]
+    fn coalesces_adjacent_freed_blocks() {
+        // Two 256-byte blocks exactly fill the heap, so the only way a later 400-byte
+        // allocation can succeed is if freeing both blocks merges them back into one
+        // contiguous 512-byte region.
+        let (allocator, _heap) = new_heap::<512>(FitPolicy::FirstFit);
+        let small = Layout::from_size_align(256, 8).unwrap();
+
+        unsafe {
+            let a = allocator.alloc(small);
+            let b = allocator.alloc(small);
+            assert!(!a.is_null() && !b.is_null());
+
+            allocator.dealloc(a, small);
+            allocator.dealloc(b, small);
+
+            let big = Layout::from_size_align(400, 8).unwrap();
+            let merged = allocator.alloc(big);
+            assert!(!merged.is_null(), "freeing adjacent blocks should have coalesced them");
+        }
+    }
+
+    #[test]
+    fn best_fit_picks_the_tightest_gap() {
+        // Four blocks exactly fill the heap (no leftover region to muddy the picture). Freeing
+        // B and D leaves two non-adjacent gaps of different sizes, both big enough for the
+        // upcoming 80-byte request; best-fit must pick D (the tighter one) even though B sits
+        // earlier in the address-ordered free list.
+        let (allocator, heap) = new_heap::<896>(FitPolicy::BestFit);
+        let heap_start = heap.0.as_ptr() as usize;
+
+        let a = Layout::from_size_align(256, 8).unwrap();
+        let b = Layout::from_size_align(192, 8).unwrap();
+        let c = Layout::from_size_align(320, 8).unwrap();
+        let d = Layout::from_size_align(128, 8).unwrap();
+
+        unsafe {
+            let a_ptr = allocator.alloc(a);
+            let b_ptr = allocator.alloc(b);
+            let c_ptr = allocator.alloc(c);
+            let d_ptr = allocator.alloc(d);
+            assert!([a_ptr, b_ptr, c_ptr, d_ptr].iter().all(|p| !p.is_null()));
+
+            allocator.dealloc(b_ptr, b);
+            allocator.dealloc(d_ptr, d);
+
+            let small = Layout::from_size_align(80, 8).unwrap();
+            let picked = allocator.alloc(small);
+            assert!(!picked.is_null());
+            assert_eq!(
+                picked as usize - heap_start,
+                d_ptr as usize - heap_start,
+                "best-fit should reuse D's 128-byte gap (excess 48), not B's 192-byte one (excess 112)"
+            );
+        }
+    }
+
+    #[test]
+    fn grows_in_place_when_the_following_block_is_free() {
+        // A single small allocation leaves the rest of the heap free and immediately adjacent to
+        // it, so growing into a much bigger layout should extend in place rather than copy.
+        let (allocator, _heap) = new_heap::<1024>(FitPolicy::FirstFit);
+        let old_layout = Layout::from_size_align(64, 8).unwrap();
+        let new_layout = Layout::from_size_align(512, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.alloc(old_layout);
+            assert!(!ptr.is_null());
+
+            let grown = Allocator::grow(&allocator, NonNull::new(ptr).unwrap(), old_layout, new_layout)
+                .expect("growth should succeed");
+
+            assert_eq!(grown.as_ptr() as *mut u8 as usize, ptr as usize);
+            assert!(grown.len() >= new_layout.size());
+        }
+    }
+
+    #[test]
+    fn shrink_frees_the_trailing_remainder() {
+        // Shrinking with the same alignment doesn't move data, but the bytes given back should be
+        // usable again - here, as a follow-up allocation landing right where they were freed.
+        let (allocator, _heap) = new_heap::<1024>(FitPolicy::FirstFit);
+        let old_layout = Layout::from_size_align(512, 8).unwrap();
+        let new_layout = Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.alloc(old_layout);
+            assert!(!ptr.is_null());
+
+            let shrunk = Allocator::shrink(&allocator, NonNull::new(ptr).unwrap(), old_layout, new_layout)
+                .expect("shrink should succeed");
+            assert_eq!(shrunk.as_ptr() as *mut u8 as usize, ptr as usize);
+
+            let remainder = allocator.alloc(Layout::from_size_align(400, 8).unwrap());
+            assert!(!remainder.is_null(), "the trailing remainder should have been freed");
+            assert_eq!(remainder as usize, ptr as usize + new_layout.size());
+        }
     }
 }